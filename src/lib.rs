@@ -0,0 +1,4 @@
+//! A library for validating and producing RPKI repository objects.
+
+pub mod manifest;
+pub mod tst;
@@ -1,10 +1,14 @@
 //! RPKI Manifests
 
-use bytes::Bytes;
+use std::{error, fmt};
+use bytes::{Bytes, BytesMut};
 use super::rsync;
-use super::ber::{BitString, Constructed, Error, Mode, OctetString, Source, Tag};
+use super::ber::{
+    encode, BitString, Constructed, Error, Mode, Oid, OctetString, Source, Tag
+};
 use super::cert::{ResourceCert};
 use super::sigobj::{self, SignedObject};
+use super::signing::SigningKey;
 use super::x509::{Time, ValidationError};
 
 
@@ -32,8 +36,94 @@ impl Manifest {
         let cert = self.signed.validate(cert)?;
         Ok((cert, self.content))
     }
+
+    /// Validates the manifest like `validate` but also checks that `now`
+    /// falls within the manifest’s `thisUpdate`/`nextUpdate` window.
+    ///
+    /// This is kept separate from `validate` so that callers who need to
+    /// tolerate a stale manifest (e.g. a repository publication point that
+    /// has gone quiet) can catch `ManifestValidationError::Stale` and apply
+    /// their own grace-period policy instead of rejecting the manifest
+    /// outright.
+    pub fn validate_at(
+        self,
+        cert: &ResourceCert,
+        now: Time,
+    ) -> Result<(ResourceCert, ManifestContent), ManifestValidationError> {
+        let (cert, content) = self.validate(cert)?;
+        if now < content.this_update {
+            return Err(ManifestValidationError::NotYetValid);
+        }
+        if now > content.next_update {
+            return Err(ManifestValidationError::Stale);
+        }
+        Ok((cert, content))
+    }
+
+    /// Signs `content` into a CMS `SignedObject` using `cert` and `key`.
+    ///
+    /// This is the inverse of `decode`: it produces the DER encoding of a
+    /// fresh manifest, ready to be published at a repository object URI.
+    pub fn sign(
+        content: ManifestContent,
+        cert: ResourceCert,
+        key: &SigningKey,
+    ) -> Result<Bytes, Error> {
+        let signed = SignedObject::sign(
+            sigobj::oid::MANIFEST.clone(),
+            content.to_captured(),
+            cert,
+            key,
+        )?;
+        Ok(signed.to_captured())
+    }
+}
+
+
+//------------ ManifestValidationError ----------------------------------------
+
+/// The ways time-aware manifest validation can fail.
+///
+/// Unlike a plain `ValidationError`, this distinguishes a manifest that
+/// simply hasn’t started its validity period yet or has gone stale from
+/// one that is outright invalid, so callers can decide for themselves
+/// whether a stale manifest is still acceptable.
+#[derive(Clone, Debug)]
+pub enum ManifestValidationError {
+    /// The manifest itself is invalid, independent of time.
+    Invalid(ValidationError),
+
+    /// `now` is earlier than the manifest’s `thisUpdate`.
+    NotYetValid,
+
+    /// `now` is later than the manifest’s `nextUpdate`.
+    Stale,
+}
+
+impl From<ValidationError> for ManifestValidationError {
+    fn from(err: ValidationError) -> Self {
+        ManifestValidationError::Invalid(err)
+    }
+}
+
+impl fmt::Display for ManifestValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestValidationError::Invalid(_) => {
+                write!(f, "invalid manifest")
+            }
+            ManifestValidationError::NotYetValid => {
+                write!(f, "manifest not yet valid")
+            }
+            ManifestValidationError::Stale => {
+                write!(f, "manifest is stale")
+            }
+        }
+    }
 }
 
+impl error::Error for ManifestValidationError { }
+
 
 //------------ ManifestContent -----------------------------------------------
 
@@ -42,6 +132,7 @@ pub struct ManifestContent {
     manifest_number: Bytes,
     this_update: Time,
     next_update: Time,
+    digest_algorithm: DigestAlgorithm,
     file_list: Bytes,
 }
 
@@ -64,7 +155,7 @@ impl ManifestContent {
             if this_update > next_update {
                 xerr!(return Err(Error::Malformed.into()));
             }
-            sigobj::oid::SHA256.skip_if(cons)?;
+            let digest_algorithm = DigestAlgorithm::take_from(cons)?;
             let file_list = cons.sequence(|cons| {
                 cons.capture(|cons| {
                     while let Some(()) = FileAndHash::skip_opt_in(cons)? {
@@ -73,34 +164,161 @@ impl ManifestContent {
                 })
             })?;
             Ok(ManifestContent {
-                manifest_number, this_update, next_update, file_list
+                manifest_number, this_update, next_update,
+                digest_algorithm, file_list
             })
         })
     }
 
     pub fn iter_uris(&self, base: rsync::Uri) -> ManifestIter {
-        ManifestIter { base, file_list: self.file_list.clone() }
+        let (entries, error) = ManifestIter::decode_entries(
+            self.file_list.clone(), self.digest_algorithm
+        );
+        ManifestIter { base, entries, next: 0, error }
+    }
+
+    pub fn manifest_number(&self) -> &Bytes {
+        &self.manifest_number
+    }
+
+    pub fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+
+    pub fn this_update(&self) -> Time {
+        self.this_update
+    }
+
+    pub fn next_update(&self) -> Time {
+        self.next_update
+    }
+
+    /// Returns a value encoder for the manifest’s eContent.
+    ///
+    /// The `file_list` field is kept as the already DER-encoded
+    /// concatenation of `FileAndHash` entries produced during decoding or
+    /// by `ManifestContentBuilder`, so it is simply wrapped in its
+    /// enclosing SEQUENCE here rather than being re-decoded.
+    pub fn encode(&self) -> impl encode::Values + '_ {
+        encode::sequence((
+            self.manifest_number.clone().encode_as(Tag::INTEGER),
+            self.this_update.encode(),
+            self.next_update.encode(),
+            self.digest_algorithm.oid().encode(),
+            encode::sequence(encode::raw(self.file_list.clone())),
+        ))
+    }
+
+    /// Encodes the manifest’s eContent into its DER representation.
+    pub fn to_captured(&self) -> Bytes {
+        self.encode().to_captured(Mode::Der).into_bytes()
+    }
+}
+
+
+//------------ ManifestContentBuilder -----------------------------------------
+
+/// A builder for producing a fresh `ManifestContent`.
+///
+/// Entries are added in publication order via `push_file`, which hashes
+/// the given object content with SHA-256 and records the resulting
+/// `FileAndHash`. The order entries are pushed in is the order they will
+/// be iterated by `ManifestIter` once the manifest is decoded back.
+pub struct ManifestContentBuilder {
+    manifest_number: Bytes,
+    this_update: Time,
+    next_update: Time,
+    digest_algorithm: DigestAlgorithm,
+    entries: BytesMut,
+}
+
+impl ManifestContentBuilder {
+    pub fn new(
+        manifest_number: Bytes,
+        this_update: Time,
+        next_update: Time,
+    ) -> Self {
+        ManifestContentBuilder {
+            manifest_number, this_update, next_update,
+            digest_algorithm: DigestAlgorithm::Sha256,
+            entries: BytesMut::new(),
+        }
+    }
+
+    /// Hashes `content` and adds `file_name` and its hash to the manifest.
+    ///
+    /// The hash is taken under the builder’s `digest_algorithm`, which
+    /// defaults to SHA-256.
+    pub fn push_file(&mut self, file_name: &str, content: &Bytes) {
+        let digest = ::ring::digest::digest(
+            self.digest_algorithm.ring_algorithm(), content.as_ref()
+        );
+        let item = FileAndHash::new(
+            file_name, self.digest_algorithm, digest.as_ref()
+        );
+        item.encode().write_encoded(
+            Mode::Der, &mut self.entries
+        ).unwrap();
+    }
+
+    /// Converts the builder into the finished `ManifestContent`.
+    pub fn into_content(self) -> ManifestContent {
+        ManifestContent {
+            manifest_number: self.manifest_number,
+            this_update: self.this_update,
+            next_update: self.next_update,
+            digest_algorithm: self.digest_algorithm,
+            file_list: self.entries.freeze(),
+        }
     }
 }
 
 
 //------------ ManifestIter --------------------------------------------------
 
+/// An iterator over the entries of a manifest’s `file_list`.
+///
+/// The entries are decoded once, up front, in `ManifestContent::iter_uris`.
+/// If a malformed trailing `FileAndHash` is encountered, decoding stops
+/// there and the error is surfaced as one final `Some(Err(_))` item rather
+/// than panicking, so a single bad entry doesn’t bring down a validation
+/// run over many repositories.
 #[derive(Clone, Debug)]
-pub struct ManifestIter{
+pub struct ManifestIter {
     base: rsync::Uri,
-    file_list: Bytes,
+    entries: Vec<FileAndHash>,
+    next: usize,
+    error: Option<ValidationError>,
+}
+
+impl ManifestIter {
+    fn decode_entries(
+        mut file_list: Bytes,
+        digest_algorithm: DigestAlgorithm,
+    ) -> (Vec<FileAndHash>, Option<ValidationError>) {
+        let mut entries = Vec::new();
+        loop {
+            let res = Mode::Ber.decode(&mut file_list, |cons| {
+                FileAndHash::take_opt_from(cons, digest_algorithm)
+            });
+            match res {
+                Ok(Some(item)) => entries.push(item),
+                Ok(None) => return (entries, None),
+                Err(_) => return (entries, Some(ValidationError)),
+            }
+        }
+    }
 }
 
 impl Iterator for ManifestIter {
     type Item = Result<(rsync::Uri, ManifestHash), ValidationError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Mode::Ber.decode(&mut self.file_list, |cons| {
-            FileAndHash::take_opt_from(cons)
-        }).unwrap().map(|item| {
-            item.to_uri_etc(&self.base)
-        })
+        if let Some(item) = self.entries.get(self.next) {
+            self.next += 1;
+            return Some(item.clone().to_uri_etc(&self.base));
+        }
+        self.error.take().map(Err)
     }
 }
 
@@ -114,6 +332,28 @@ pub struct FileAndHash {
 }
 
 impl FileAndHash {
+    /// Creates a `FileAndHash` from a file name and its raw digest bytes.
+    fn new(
+        file_name: &str, algorithm: DigestAlgorithm, digest: &[u8]
+    ) -> Self {
+        FileAndHash {
+            file: OctetString::new(
+                Bytes::copy_from_slice(file_name.as_bytes())
+            ),
+            hash: ManifestHash(
+                algorithm,
+                BitString::new(0, Bytes::copy_from_slice(digest)),
+            ),
+        }
+    }
+
+    fn encode(&self) -> impl encode::Values + '_ {
+        encode::sequence((
+            self.file.clone().encode_as(Tag::IA5_STRING),
+            self.hash.1.encode(),
+        ))
+    }
+
     fn skip_opt_in<S: Source>(
         cons: &mut Constructed<S>
     ) -> Result<Option<()>, S::Err> {
@@ -125,7 +365,8 @@ impl FileAndHash {
     }
 
     fn take_opt_from<S: Source>(
-        cons: &mut Constructed<S>
+        cons: &mut Constructed<S>,
+        digest_algorithm: DigestAlgorithm,
     ) -> Result<Option<Self>, S::Err> {
         cons.opt_sequence(|cons| {
             Ok(FileAndHash {
@@ -133,7 +374,9 @@ impl FileAndHash {
                     Tag::IA5_STRING,
                     OctetString::take_content_from
                 )?,
-                hash: ManifestHash(BitString::take_from(cons)?)
+                hash: ManifestHash(
+                    digest_algorithm, BitString::take_from(cons)?
+                )
             })
         })
     }
@@ -157,7 +400,7 @@ impl FileAndHash {
 //------------ ManifestHash --------------------------------------------------
 
 #[derive(Clone, Debug)]
-pub struct ManifestHash(BitString);
+pub struct ManifestHash(DigestAlgorithm, BitString);
 
 impl ManifestHash {
     pub fn verify<B: AsRef<[u8]>>(
@@ -165,11 +408,122 @@ impl ManifestHash {
         bytes: B
     ) -> Result<(), ValidationError> {
         ::ring::constant_time::verify_slices_are_equal(
-            self.0.octet_slice().unwrap(),
+            self.1.octet_slice().unwrap(),
             ::ring::digest::digest(
-                &::ring::digest::SHA256,
+                self.0.ring_algorithm(),
                 bytes.as_ref()
             ).as_ref()
         ).map_err(|_| ValidationError)
     }
 }
+
+
+//------------ DigestAlgorithm ------------------------------------------------
+
+/// OID constants for the digest algorithms `DigestAlgorithm` supports but
+/// that `sigobj::oid` does not (yet) define.
+mod oid {
+    use bytes::Bytes;
+    use super::Oid;
+
+    pub const SHA384: Oid = Oid(Bytes::from_static(&[
+        0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02
+    ]));
+    pub const SHA512: Oid = Oid(Bytes::from_static(&[
+        0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03
+    ]));
+}
+
+/// The hash algorithm used for the `fileHashAlg` field and file digests.
+///
+/// Today’s RPKI manifests are always SHA-256, but the `fileHashAlg` OID
+/// is read and checked explicitly so the crate can follow the profile if
+/// it ever allows a stronger algorithm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub(crate) fn oid(self) -> &'static Oid {
+        match self {
+            DigestAlgorithm::Sha256 => &sigobj::oid::SHA256,
+            DigestAlgorithm::Sha384 => &oid::SHA384,
+            DigestAlgorithm::Sha512 => &oid::SHA512,
+        }
+    }
+
+    pub(crate) fn ring_algorithm(self) -> &'static ::ring::digest::Algorithm {
+        match self {
+            DigestAlgorithm::Sha256 => &::ring::digest::SHA256,
+            DigestAlgorithm::Sha384 => &::ring::digest::SHA384,
+            DigestAlgorithm::Sha512 => &::ring::digest::SHA512,
+        }
+    }
+
+    /// Reads a bare digest OID, rejecting unknown algorithms.
+    ///
+    /// This is for fields like `fileHashAlg` that are encoded as a plain
+    /// `OBJECT IDENTIFIER` rather than a full `AlgorithmIdentifier`. Use
+    /// `take_from_algorithm_identifier` for the latter.
+    pub(crate) fn take_from<S: Source>(
+        cons: &mut Constructed<S>
+    ) -> Result<Self, S::Err> {
+        if let Some(()) = sigobj::oid::SHA256.skip_opt_if(cons)? {
+            return Ok(DigestAlgorithm::Sha256)
+        }
+        if let Some(()) = oid::SHA384.skip_opt_if(cons)? {
+            return Ok(DigestAlgorithm::Sha384)
+        }
+        if let Some(()) = oid::SHA512.skip_opt_if(cons)? {
+            return Ok(DigestAlgorithm::Sha512)
+        }
+        xerr!(Err(Error::Malformed.into()))
+    }
+
+    /// Reads a full `AlgorithmIdentifier ::= SEQUENCE { algorithm OID,
+    /// parameters ANY OPTIONAL }`, rejecting unknown algorithms.
+    ///
+    /// Used for fields such as a time-stamp token’s `messageImprint.
+    /// hashAlgorithm`, which (unlike `fileHashAlg`) is wrapped in its own
+    /// SEQUENCE and may carry a trailing `parameters` value.
+    pub(crate) fn take_from_algorithm_identifier<S: Source>(
+        cons: &mut Constructed<S>
+    ) -> Result<Self, S::Err> {
+        cons.sequence(|cons| {
+            let algorithm = Self::take_from(cons)?;
+            cons.skip_all()?;
+            Ok(algorithm)
+        })
+    }
+}
+
+
+//------------ Tests ----------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_content_round_trips_through_encode_and_decode() {
+        let this_update = Time::now();
+        let next_update = this_update;
+        let mut builder = ManifestContentBuilder::new(
+            Bytes::from_static(&[1]), this_update, next_update
+        );
+        builder.push_file("test.roa", &Bytes::from_static(b"some content"));
+        let built = builder.into_content();
+
+        let mut captured = built.to_captured();
+        let decoded = Mode::Ber.decode(&mut captured, |cons| {
+            ManifestContent::decode(cons)
+        }).unwrap();
+
+        assert_eq!(decoded.manifest_number, built.manifest_number);
+        assert_eq!(decoded.digest_algorithm, built.digest_algorithm);
+        assert_eq!(decoded.file_list, built.file_list);
+    }
+}
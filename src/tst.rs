@@ -0,0 +1,121 @@
+//! RFC 3161 time-stamp tokens.
+//!
+//! A time-stamp token is itself a CMS `SignedObject` whose eContent is a
+//! `TSTInfo`. This module lets an operator prove that a given manifest
+//! existed at a trusted time, independent of the manifest's own
+//! `thisUpdate`/`nextUpdate` window.
+
+use bytes::Bytes;
+use super::ber::{Constructed, Error, Oid, OctetString, Source, Tag};
+use super::cert::ResourceCert;
+use super::manifest::DigestAlgorithm;
+use super::sigobj::SignedObject;
+use super::x509::{Time, ValidationError};
+
+
+//------------ TimeStampToken --------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct TimeStampToken {
+    signed: SignedObject,
+    info: TstInfo,
+}
+
+impl TimeStampToken {
+    pub fn decode<S: Source>(source: S) -> Result<Self, S::Err> {
+        let signed = SignedObject::decode(source)?;
+        let info = signed.decode_content(|cons| TstInfo::decode(cons))?;
+        Ok(TimeStampToken { signed, info })
+    }
+
+    /// Verifies this token was issued by `tsa_cert` over `manifest_bytes`.
+    ///
+    /// Validates the token's own CMS signature, then recomputes the
+    /// digest of `manifest_bytes` under the `messageImprint`'s declared
+    /// algorithm and compares it in constant time against `hashedMessage`.
+    /// On success, returns the token's `genTime`.
+    pub fn verify_against(
+        &self,
+        manifest_bytes: &[u8],
+        tsa_cert: &ResourceCert,
+    ) -> Result<Time, ValidationError> {
+        self.signed.clone().validate(tsa_cert)?;
+        let digest = ::ring::digest::digest(
+            self.info.message_imprint.hash_algorithm.ring_algorithm(),
+            manifest_bytes,
+        );
+        ::ring::constant_time::verify_slices_are_equal(
+            self.info.message_imprint.hashed_message.as_ref(),
+            digest.as_ref(),
+        ).map_err(|_| ValidationError)?;
+        Ok(self.info.gen_time)
+    }
+
+    pub fn serial_number(&self) -> &Bytes {
+        &self.info.serial_number
+    }
+
+    pub fn nonce(&self) -> Option<&Bytes> {
+        self.info.nonce.as_ref()
+    }
+}
+
+
+//------------ TstInfo -----------------------------------------------------
+
+#[derive(Clone, Debug)]
+struct TstInfo {
+    serial_number: Bytes,
+    message_imprint: MessageImprint,
+    gen_time: Time,
+    nonce: Option<Bytes>,
+}
+
+impl TstInfo {
+    fn decode<S: Source>(
+        cons: &mut Constructed<S>
+    ) -> Result<Self, S::Err> {
+        cons.sequence(|cons| {
+            let version = cons.take_primitive_if(
+                Tag::INTEGER, |prim| prim.to_u8()
+            )?;
+            if version != 1 {
+                xerr!(return Err(Error::Malformed.into()));
+            }
+            Oid::skip_in(cons)?;
+            let message_imprint = MessageImprint::take_from(cons)?;
+            let serial_number = cons.take_unsigned()?;
+            let gen_time = Time::take_from(cons)?;
+            // Accuracy ::= SEQUENCE, ordering ::= BOOLEAN, both unused.
+            cons.opt_sequence(|cons| cons.skip_all())?;
+            cons.opt_primitive_if(Tag::BOOLEAN, |_| Ok(()))?;
+            let nonce = cons.take_opt_unsigned()?;
+            // tsa [0] GeneralName, extensions [1] Extensions: unused, but
+            // must still be consumed for the SEQUENCE to close cleanly.
+            cons.skip_all()?;
+            Ok(TstInfo { serial_number, message_imprint, gen_time, nonce })
+        })
+    }
+}
+
+
+//------------ MessageImprint -----------------------------------------------
+
+#[derive(Clone, Debug)]
+struct MessageImprint {
+    hash_algorithm: DigestAlgorithm,
+    hashed_message: Bytes,
+}
+
+impl MessageImprint {
+    fn take_from<S: Source>(
+        cons: &mut Constructed<S>
+    ) -> Result<Self, S::Err> {
+        cons.sequence(|cons| {
+            let hash_algorithm =
+                DigestAlgorithm::take_from_algorithm_identifier(cons)?;
+            let hashed_message = OctetString::take_from(cons)?.to_bytes();
+            Ok(MessageImprint { hash_algorithm, hashed_message })
+        })
+    }
+}